@@ -0,0 +1,235 @@
+use chrono::NaiveDate;
+use serde_json::Value;
+
+use crate::structures::Record;
+
+/// A comparison to apply to a [`Predicate`]'s field
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Contains,
+    Between,
+}
+
+/// The typed value a [`Predicate`] compares a field against
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Text(String),
+    Number(f64),
+    Date(NaiveDate),
+    DateRange(NaiveDate, NaiveDate),
+}
+
+/// A single comparison against one field of a [`Record`]
+///
+/// `field` is either one of the core fields (`areaType`, `areaName`, `areaCode`,
+/// `date`) or the name of a requested metric, looked up in `Record::extra`. A
+/// comparison against a missing or null metric evaluates to `false` rather than
+/// erroring
+#[derive(Debug, Clone, PartialEq)]
+pub struct Predicate {
+    pub field: String,
+    pub op: Op,
+    pub operand: Operand,
+}
+
+impl Predicate {
+    pub fn new(field: impl Into<String>, op: Op, operand: Operand) -> Predicate {
+        Predicate {
+            field: field.into(),
+            op,
+            operand,
+        }
+    }
+
+    /// Evaluates this predicate against a single record
+    pub fn evaluate(&self, record: &Record) -> bool {
+        match self.field.as_str() {
+            "areaType" => self.compare_str(record.area_type.as_str()),
+            "areaName" => self.compare_str(record.area_name.as_str()),
+            "areaCode" => self.compare_str(record.area_code.as_str()),
+            "date" => self.compare_date(record.date),
+            field => match record.extra.get(field) {
+                Some(value) => self.compare_value(value),
+                None => false,
+            },
+        }
+    }
+
+    fn compare_str(&self, value: &str) -> bool {
+        match (&self.op, &self.operand) {
+            (Op::Eq, Operand::Text(text)) => value == text,
+            (Op::Ne, Operand::Text(text)) => value != text,
+            (Op::Contains, Operand::Text(text)) => value.contains(text.as_str()),
+            _ => false,
+        }
+    }
+
+    fn compare_date(&self, value: NaiveDate) -> bool {
+        match (&self.op, &self.operand) {
+            (Op::Eq, Operand::Date(date)) => value == *date,
+            (Op::Ne, Operand::Date(date)) => value != *date,
+            (Op::Gt, Operand::Date(date)) => value > *date,
+            (Op::Ge, Operand::Date(date)) => value >= *date,
+            (Op::Lt, Operand::Date(date)) => value < *date,
+            (Op::Le, Operand::Date(date)) => value <= *date,
+            (Op::Between, Operand::DateRange(start, end)) => value >= *start && value <= *end,
+            _ => false,
+        }
+    }
+
+    fn compare_value(&self, value: &Value) -> bool {
+        match &self.operand {
+            Operand::Number(number) => match value.as_f64() {
+                Some(actual) => match self.op {
+                    Op::Eq => (actual - number).abs() < f64::EPSILON,
+                    Op::Ne => (actual - number).abs() >= f64::EPSILON,
+                    Op::Gt => actual > *number,
+                    Op::Ge => actual >= *number,
+                    Op::Lt => actual < *number,
+                    Op::Le => actual <= *number,
+                    _ => false,
+                },
+                None => false,
+            },
+            Operand::Text(text) => match value.as_str() {
+                Some(actual) => match self.op {
+                    Op::Eq => actual == text,
+                    Op::Ne => actual != text,
+                    Op::Contains => actual.contains(text.as_str()),
+                    _ => false,
+                },
+                None => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+/// An expression tree of [`Predicate`]s combined with `And`/`Or`/`Not`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Predicate(Predicate),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+}
+
+impl Expr {
+    /// Walks the expression tree, evaluating every predicate against the record
+    pub fn evaluate(&self, record: &Record) -> bool {
+        match self {
+            Expr::Predicate(predicate) => predicate.evaluate(record),
+            Expr::And(left, right) => left.evaluate(record) && right.evaluate(record),
+            Expr::Or(left, right) => left.evaluate(record) || right.evaluate(record),
+            Expr::Not(inner) => !inner.evaluate(record),
+        }
+    }
+
+    pub fn and(self, other: Expr) -> Expr {
+        Expr::And(Box::new(self), Box::new(other))
+    }
+
+    pub fn or(self, other: Expr) -> Expr {
+        Expr::Or(Box::new(self), Box::new(other))
+    }
+
+    pub fn not(self) -> Expr {
+        Expr::Not(Box::new(self))
+    }
+}
+
+impl From<Predicate> for Expr {
+    fn from(predicate: Predicate) -> Self {
+        Expr::Predicate(predicate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use super::*;
+
+    fn record(date: NaiveDate, new_cases: Option<f64>) -> Record {
+        let mut extra = HashMap::new();
+        if let Some(new_cases) = new_cases {
+            extra.insert(String::from("newCasesByPublishDate"), json!(new_cases));
+        }
+        Record {
+            area_type: String::from("nation"),
+            area_name: String::from("england"),
+            area_code: String::from("E92000001"),
+            date,
+            extra,
+        }
+    }
+
+    #[test]
+    fn eq_matches_core_field() {
+        let predicate = Predicate::new("areaName", Op::Eq, Operand::Text(String::from("england")));
+        let record = record(NaiveDate::from_ymd(2020, 4, 1), None);
+        assert!(predicate.evaluate(&record));
+    }
+
+    #[test]
+    fn gt_matches_metric_in_extra() {
+        let predicate = Predicate::new("newCasesByPublishDate", Op::Gt, Operand::Number(1000.0));
+        let above = record(NaiveDate::from_ymd(2020, 4, 1), Some(1500.0));
+        let below = record(NaiveDate::from_ymd(2020, 4, 1), Some(500.0));
+        assert!(predicate.evaluate(&above));
+        assert!(!predicate.evaluate(&below));
+    }
+
+    #[test]
+    fn missing_metric_is_false_not_error() {
+        let predicate = Predicate::new("newCasesByPublishDate", Op::Gt, Operand::Number(1000.0));
+        let record = record(NaiveDate::from_ymd(2020, 4, 1), None);
+        assert!(!predicate.evaluate(&record));
+    }
+
+    #[test]
+    fn between_matches_date_range() {
+        let predicate = Predicate::new(
+            "date",
+            Op::Between,
+            Operand::DateRange(
+                NaiveDate::from_ymd(2020, 3, 1),
+                NaiveDate::from_ymd(2020, 6, 1),
+            ),
+        );
+        let inside = record(NaiveDate::from_ymd(2020, 4, 1), None);
+        let outside = record(NaiveDate::from_ymd(2020, 7, 1), None);
+        assert!(predicate.evaluate(&inside));
+        assert!(!predicate.evaluate(&outside));
+    }
+
+    #[test]
+    fn and_or_not_combine_predicates() {
+        let date_in_range: Expr = Predicate::new(
+            "date",
+            Op::Between,
+            Operand::DateRange(
+                NaiveDate::from_ymd(2020, 3, 1),
+                NaiveDate::from_ymd(2020, 6, 1),
+            ),
+        )
+        .into();
+        let cases_above_threshold: Expr =
+            Predicate::new("newCasesByPublishDate", Op::Gt, Operand::Number(1000.0)).into();
+        let expr = date_in_range.and(cases_above_threshold);
+
+        let matches = record(NaiveDate::from_ymd(2020, 4, 1), Some(1500.0));
+        let wrong_date = record(NaiveDate::from_ymd(2020, 7, 1), Some(1500.0));
+        assert!(expr.evaluate(&matches));
+        assert!(!expr.evaluate(&wrong_date));
+        assert!(expr.clone().not().evaluate(&wrong_date));
+    }
+}
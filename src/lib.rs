@@ -11,10 +11,14 @@ use serde_json::Value;
 
 use structures::Filters;
 
+use crate::filter::Expr;
 use crate::structures::{ApiError, ApiErrorKind, AreaType, Structures};
 
+pub mod filter;
 mod structures;
 
+pub use crate::structures::{FilterSet, OutputFormat, Record, StructureNode};
+
 /// Coronavirus (COVID-19) Dashboard - API Service
 /// ==============================================
 /// Software Development Kit (SDK)
@@ -29,11 +33,23 @@ pub const ENDPOINT: &str = "https://api.coronavirus.data.gov.uk/v1/data";
 
 #[derive(Default)]
 pub struct Cov19api {
-    filters: HashMap<String, String>,
+    filters: FilterSet,
     structure: HashMap<String, String>,
+    structure_nested: HashMap<String, StructureNode>,
+    format: OutputFormat,
     client: Client,
 }
 
+/// The raw body of an API response
+///
+/// `send_request` returns `Response::Json` when `OutputFormat::Json` is selected,
+/// and `Response::Text` (the unparsed body) for every other `OutputFormat`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    Json(Value),
+    Text(String),
+}
+
 impl Cov19api {
     /// Instantiates a new Cov19api instance
     /// And builds a reqwest client
@@ -44,6 +60,8 @@ impl Cov19api {
         Cov19api {
             filters: Default::default(),
             structure: Default::default(),
+            structure_nested: Default::default(),
+            format: Default::default(),
             client,
         }
     }
@@ -51,6 +69,14 @@ impl Cov19api {
     pub fn clear(&mut self) {
         self.filters.clear();
         self.structure.clear();
+        self.structure_nested.clear();
+    }
+    /// Sets the response format the API should return
+    ///
+    /// Defaults to `OutputFormat::Json`; non-JSON formats are returned as the raw
+    /// response body from `send_request` since they cannot be parsed into a `Value`
+    pub fn set_format(&mut self, format: OutputFormat) {
+        self.format = format;
     }
     /// Adds the given filter to the request
     /// Provided it is a valid field, and the value is correct
@@ -73,29 +99,9 @@ impl Cov19api {
                 msg,
             }));
         }
-        if filter_name.eq("areaType") && !AreaType::to_vec().contains(&filter_value) {
-            let msg = format!(
-                "Invalid area type provided!\nNeeds to be one of: {:?}",
-                AreaType::to_string()
-            );
-            println!("{}", msg);
-            return Err(Box::new(ApiError {
-                kind: ApiErrorKind::InvalidFilterValue,
-                msg,
-            }));
-        }
-        let date_regex =
-            Regex::new(r"^\d{4}\-(0?[1-9]|1[012])\-(0?[1-9]|[12][0-9]|3[01])$").unwrap();
-        if filter_name.eq("date") && date_regex.is_match(&filter_value.as_str()) {
-            let msg = String::from("Invalid date provided!\nNeeds to be in the format YYYY-MM-DD");
-            println!("{}", msg);
-            return Err(Box::new(ApiError {
-                kind: ApiErrorKind::InvalidFilterValue,
-                msg,
-            }));
-        }
-        self.filters.insert(filter_name, filter_value);
-        Ok(())
+        let filter_name = Filters::parse(filter_name.as_str())
+            .expect("filter_name was validated against Filters::to_vec() above");
+        self.set_filter_enum(filter_name, filter_value)
     }
 
     /// Adds the given filter to the request
@@ -121,7 +127,7 @@ impl Cov19api {
         }
         let date_regex =
             Regex::new(r"^\d{4}\-(0?[1-9]|1[012])\-(0?[1-9]|[12][0-9]|3[01])$").unwrap();
-        if filter_name == Filters::date && date_regex.is_match(&filter_value.as_str()) {
+        if filter_name == Filters::date && !date_regex.is_match(&filter_value.as_str()) {
             let msg = String::from("Invalid date provided!\nNeeds to be in the format YYYY-MM-DD");
             println!("{}", msg);
             return Err(Box::new(ApiError {
@@ -129,9 +135,7 @@ impl Cov19api {
                 msg,
             }));
         }
-        self.filters
-            .insert(format!("{:?}", filter_name), filter_value);
-        Ok(())
+        self.filters.insert(filter_name, filter_value)
     }
 
     /// Requests a metric from the covid api
@@ -179,24 +183,51 @@ impl Cov19api {
         Ok(())
     }
 
-    pub async fn send_request(&mut self) -> Result<Value, Box<dyn std::error::Error>> {
+    /// Requests a metric from the covid api using a nested [`StructureNode`]
+    /// instead of a simple rename
+    ///
+    /// Needed for age-demographic metrics such as `maleCases`, `femaleCases` and
+    /// `cumAdmissionsByAge`, which the API returns as arrays of `{ "age", "value" }`
+    /// objects rather than a single scalar
+    pub fn set_structure_nested(
+        &mut self,
+        structure_name: String,
+        node: StructureNode,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if !Structures::to_vec().contains(&structure_name) {
+            let msg = format!(
+                "Invalid structure name provided!\nNeeds to be one of: {}",
+                Structures::to_string()
+            );
+            println!("{}", msg);
+            return Err(Box::new(ApiError {
+                kind: ApiErrorKind::InvalidStructure,
+                msg,
+            }));
+        }
+        self.structure_nested.insert(structure_name, node);
+        Ok(())
+    }
+
+    pub async fn send_request(&mut self) -> Result<Response, Box<dyn std::error::Error>> {
         let mut url: String = String::from(ENDPOINT);
         if !self.filters.is_empty() {
             url.push_str("?filters=");
             let mut index = 0;
             for (filter_name, filter_value) in self.filters.iter() {
-                url.push_str(filter_name.as_str());
+                url.push_str(&format!("{:?}", filter_name));
                 url.push_str("=");
                 url.push_str(filter_value.as_str());
-                if index + 1 != self.filters.len() {
+                index += 1;
+                if index != self.filters.len() {
                     url.push_str(";");
                 }
-                index += 1;
             }
         }
-        if !self.structure.is_empty() {
+        if !self.structure.is_empty() || !self.structure_nested.is_empty() {
             url.push_str(if self.filters.is_empty() { "?" } else { "&" });
             url.push_str("structure={");
+            let total = self.structure.len() + self.structure_nested.len();
             let mut index = 0;
             for (structure_name, structure_value) in self.structure.iter() {
                 url.push_str("\"");
@@ -204,22 +235,113 @@ impl Cov19api {
                 url.push_str("\":\"");
                 url.push_str(structure_value.as_str());
                 url.push_str("\"");
-                if index + 1 != self.structure.len() {
+                index += 1;
+                if index != total {
                     url.push_str(",");
                 }
+            }
+            for (structure_name, node) in self.structure_nested.iter() {
+                url.push_str("\"");
+                url.push_str(structure_name.as_str());
+                url.push_str("\":");
+                url.push_str(&node.to_json_fragment());
                 index += 1;
+                if index != total {
+                    url.push_str(",");
+                }
             }
             url.push_str("}");
         }
-        url.push_str("&format=json");
+        url.push_str("&format=");
+        url.push_str(&self.format.to_string());
         url.push_str("&page=1");
         println!("Url: {}", url);
         let response = self.client.get(url.as_str()).send().await?;
-        let json = response.json::<Value>().await?;
-        println!("{:#?}", json);
-        println!("{:#?}", json["data"]);
-        Ok(json)
+        if self.format.is_json() {
+            let json = response.json::<Value>().await?;
+            println!("{:#?}", json);
+            println!("{:#?}", json["data"]);
+            Ok(Response::Json(json))
+        } else {
+            let text = response.text().await?;
+            Ok(Response::Text(text))
+        }
     }
+
+    /// Requests every page of the current filter/structure query and concatenates
+    /// their `data` arrays into a single result
+    ///
+    /// The API paginates results and only `send_request` is used to fetch the first
+    /// page, after which `pagination.next` (a relative path against `ENDPOINT`'s host)
+    /// is followed until it is `null`
+    pub async fn send_request_all(&mut self) -> Result<Value, Box<dyn std::error::Error>> {
+        let mut combined_data: Vec<Value> = Vec::new();
+        let mut json = match self.send_request().await? {
+            Response::Json(json) => json,
+            Response::Text(_) => {
+                let msg = String::from("send_request_all requires OutputFormat::Json");
+                println!("{}", msg);
+                return Err(Box::new(ApiError {
+                    kind: ApiErrorKind::InvalidFormat,
+                    msg,
+                }));
+            }
+        };
+        let host = ENDPOINT
+            .find("/v1/data")
+            .map(|index| &ENDPOINT[..index])
+            .unwrap_or(ENDPOINT);
+        loop {
+            append_page_data(&mut combined_data, &json);
+            match next_page_url(host, &json) {
+                Some(url) => {
+                    let response = self.client.get(url.as_str()).send().await?;
+                    json = response.json::<Value>().await?;
+                }
+                None => break,
+            }
+        }
+        Ok(Value::Array(combined_data))
+    }
+
+    /// Requests every page of the current filter/structure query, like
+    /// `send_request_all`, and deserializes each element of `data` into a [`Record`]
+    pub async fn get_records(&mut self) -> Result<Vec<Record>, Box<dyn std::error::Error>> {
+        let data = self.send_request_all().await?;
+        let records = serde_json::from_value::<Vec<Record>>(data)?;
+        Ok(records)
+    }
+
+    /// Fetches every record, like `get_records`, then retains only those matching
+    /// the given filter expression
+    ///
+    /// Unlike `set_filter_string`/`set_filter_enum`, which the server restricts to
+    /// equality on `areaType`/`areaName`/`areaCode`/`date`, this runs client-side
+    /// against the deserialized records, so it also supports ordering, ranges and
+    /// comparisons against the requested metrics
+    pub async fn query(&mut self, predicate: Expr) -> Result<Vec<Record>, Box<dyn std::error::Error>> {
+        let records = self.get_records().await?;
+        Ok(records
+            .into_iter()
+            .filter(|record| predicate.evaluate(record))
+            .collect())
+    }
+}
+
+/// Appends a page's `data` array onto the running result set
+fn append_page_data(combined: &mut Vec<Value>, json: &Value) {
+    if let Some(data) = json["data"].as_array() {
+        combined.extend(data.iter().cloned());
+    }
+}
+
+/// Resolves the relative path in `pagination.next` against the API host
+///
+/// Returns `None` once pagination is exhausted (`next` is `null`)
+fn next_page_url(host: &str, json: &Value) -> Option<String> {
+    json["pagination"]["next"]
+        .as_str()
+        .map(|next| format!("{}{}", host, next))
 }
 
 pub fn test() {
@@ -240,8 +362,63 @@ pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 #[cfg(test)]
 mod tests {
+    use serde_json::json;
+
+    use super::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn set_filter_enum_accepts_a_valid_date() {
+        let mut api = Cov19api::new();
+        assert!(api
+            .set_filter_enum(Filters::date, String::from("2020-04-01"))
+            .is_ok());
+    }
+
+    #[test]
+    fn set_filter_enum_rejects_an_invalid_date() {
+        let mut api = Cov19api::new();
+        assert!(api
+            .set_filter_enum(Filters::date, String::from("not-a-date"))
+            .is_err());
+    }
+
+    #[test]
+    fn append_page_data_extends_the_combined_result_with_each_page() {
+        let mut combined = Vec::new();
+        append_page_data(&mut combined, &json!({"data": [{"areaCode": "E92000001"}]}));
+        append_page_data(&mut combined, &json!({"data": [{"areaCode": "E12000001"}]}));
+        assert_eq!(
+            combined,
+            vec![json!({"areaCode": "E92000001"}), json!({"areaCode": "E12000001"})]
+        );
+    }
+
+    #[test]
+    fn append_page_data_ignores_a_page_with_no_data_array() {
+        let mut combined = Vec::new();
+        append_page_data(&mut combined, &json!({}));
+        assert!(combined.is_empty());
+    }
+
+    #[test]
+    fn next_page_url_resolves_a_relative_path_against_the_host() {
+        let json = json!({"pagination": {"next": "/v1/data?filters=areaType=nation&page=2"}});
+        assert_eq!(
+            next_page_url("https://api.coronavirus.data.gov.uk", &json),
+            Some(String::from(
+                "https://api.coronavirus.data.gov.uk/v1/data?filters=areaType=nation&page=2"
+            ))
+        );
+    }
+
+    #[test]
+    fn next_page_url_is_none_on_the_last_page() {
+        let json = json!({"pagination": {"next": Value::Null}});
+        assert_eq!(next_page_url("https://api.coronavirus.data.gov.uk", &json), None);
+    }
 }
@@ -1,6 +1,12 @@
+use std::collections::HashMap;
 use std::fmt;
 use std::fmt::Write;
 
+use chrono::NaiveDate;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+
 use crate::strum::IntoEnumIterator;
 
 #[derive(Debug, Clone, PartialEq)]
@@ -14,6 +20,7 @@ pub enum ApiErrorKind {
     InvalidFilter,
     InvalidFilterValue,
     InvalidStructure,
+    InvalidFormat,
 }
 
 impl fmt::Display for ApiError {
@@ -24,7 +31,7 @@ impl fmt::Display for ApiError {
 
 impl std::error::Error for ApiError {}
 
-#[derive(EnumIter, Debug, PartialEq)]
+#[derive(EnumIter, Debug, Clone, PartialEq)]
 /// All valid Filter fields
 ///
 /// Given by: https://coronavirus.data.gov.uk/developers-guide#params-filters
@@ -70,6 +77,135 @@ impl Filters {
 \n date - Date as string [YYYY-MM-DD]",
         )
     }
+    /// Parses a filter field name into its enum variant
+    ///
+    /// Returns `None` if `value` isn't one of `Filters::to_vec()`
+    pub fn parse(value: &str) -> Option<Filters> {
+        match value {
+            "areaType" => Some(Filters::areaType),
+            "areaName" => Some(Filters::areaName),
+            "areaCode" => Some(Filters::areaCode),
+            "date" => Some(Filters::date),
+            _ => None,
+        }
+    }
+}
+
+/// An ordered, deduplicated set of filters
+///
+/// Unlike a `HashMap`, insertion order is preserved (so identical logical queries
+/// always produce identical, cache-friendly URLs), contradictory `areaType`
+/// duplicates are rejected, and `areaCode` values are validated against the ONS
+/// code pattern expected for the currently-set `areaType`
+#[derive(Default, Debug, Clone)]
+pub struct FilterSet {
+    filters: Vec<(Filters, String)>,
+}
+
+impl FilterSet {
+    pub fn new() -> FilterSet {
+        Default::default()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+    pub fn len(&self) -> usize {
+        self.filters.len()
+    }
+    pub fn clear(&mut self) {
+        self.filters.clear()
+    }
+    pub fn iter(&self) -> std::slice::Iter<(Filters, String)> {
+        self.filters.iter()
+    }
+    /// Inserts a filter, replacing any existing value for the same field
+    ///
+    /// Rejects a second, differing `areaType` rather than silently overwriting it.
+    /// `areaCode` is validated against the ONS code pattern for whichever `areaType`
+    /// is set, regardless of which of the two is inserted first: inserting
+    /// `areaCode` checks it against an already-set `areaType`, and inserting
+    /// `areaType` re-checks it against an already-set `areaCode`. Validation is
+    /// skipped while the other of the pair is still unset
+    pub fn insert(
+        &mut self,
+        filter_name: Filters,
+        filter_value: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if filter_name == Filters::areaType {
+            if let Some((_, existing_value)) =
+                self.filters.iter().find(|(name, _)| name == &Filters::areaType)
+            {
+                if existing_value != &filter_value {
+                    let msg = format!(
+                        "Conflicting areaType filter!\nAlready set to {}, cannot also set {}",
+                        existing_value, filter_value
+                    );
+                    println!("{}", msg);
+                    return Err(Box::new(ApiError {
+                        kind: ApiErrorKind::InvalidFilterValue,
+                        msg,
+                    }));
+                }
+            }
+            if let Some((_, area_code)) =
+                self.filters.iter().find(|(name, _)| name == &Filters::areaCode)
+            {
+                let pattern = FilterSet::area_code_pattern(filter_value.as_str());
+                if !pattern.is_match(area_code.as_str()) {
+                    let msg = format!(
+                        "areaCode {} does not match areaType {}!\nNeeds to match: {}",
+                        area_code,
+                        filter_value,
+                        pattern.as_str()
+                    );
+                    println!("{}", msg);
+                    return Err(Box::new(ApiError {
+                        kind: ApiErrorKind::InvalidFilterValue,
+                        msg,
+                    }));
+                }
+            }
+        }
+        if filter_name == Filters::areaCode {
+            if let Some((_, area_type)) =
+                self.filters.iter().find(|(name, _)| name == &Filters::areaType)
+            {
+                let pattern = FilterSet::area_code_pattern(area_type.as_str());
+                if !pattern.is_match(filter_value.as_str()) {
+                    let msg = format!(
+                        "Invalid areaCode provided for areaType {}!\nNeeds to match: {}",
+                        area_type,
+                        pattern.as_str()
+                    );
+                    println!("{}", msg);
+                    return Err(Box::new(ApiError {
+                        kind: ApiErrorKind::InvalidFilterValue,
+                        msg,
+                    }));
+                }
+            }
+        }
+        match self.filters.iter().position(|(name, _)| *name == filter_name) {
+            Some(position) => self.filters[position].1 = filter_value,
+            None => self.filters.push((filter_name, filter_value)),
+        }
+        Ok(())
+    }
+    /// The ONS area code pattern expected for a given `areaType`
+    ///
+    /// Given by: https://coronavirus.data.gov.uk/developers-guide#params-filters
+    fn area_code_pattern(area_type: &str) -> Regex {
+        let pattern = match area_type {
+            "overview" => r"^K\d{8}$",
+            "nation" => r"^[ENSW]92\d{6}$",
+            "region" => r"^E12\d{6}$",
+            "nhsRegion" => r"^E40\d{6}$",
+            "utla" => r"^(E0[6-9]|E10|W06|S12|N09)\d{6}$",
+            "ltla" => r"^(E0[6-9]|E10|W0[6-7]|S12|N09)\d{6}$",
+            _ => r"^.*$",
+        };
+        Regex::new(pattern).unwrap()
+    }
 }
 
 #[derive(EnumIter, Debug)]
@@ -277,3 +413,244 @@ impl Structures {
 \n cumDeaths28DaysByDeathDateRate - Rate of cumulative deaths within 28 days of positive test by death date per 100k resident population")
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// The response format requested from the API
+///
+/// Given by: https://coronavirus.data.gov.uk/developers-guide#params-format
+///
+/// Only `Json` can be parsed into a `serde_json::Value`; the other formats are
+/// returned as the raw response body
+pub enum OutputFormat {
+    Json,
+    Csv,
+    Xml,
+    Jsonl,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat::Json
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let format_str = match self {
+            OutputFormat::Json => "json",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Xml => "xml",
+            OutputFormat::Jsonl => "jsonl",
+        };
+        write!(f, "{}", format_str)
+    }
+}
+
+impl OutputFormat {
+    /// Whether this format's response body can be parsed as a `serde_json::Value`
+    ///
+    /// Only `Json` can; `send_request` returns every other format as raw text
+    /// (`Response::Text`) rather than forcing a JSON parse that would fail with an error
+    pub fn is_json(&self) -> bool {
+        matches!(self, OutputFormat::Json)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A node of a nested `structure` request
+///
+/// The API's `structure` parameter is usually a flat object of renamed fields, but
+/// age-demographic metrics like `maleCases`/`femaleCases`/`cumAdmissionsByAge` are
+/// returned as arrays of `{ "age", "value" }` objects, so their requested structure
+/// needs to mirror that shape rather than a single renamed string
+pub enum StructureNode {
+    /// A renamed field, e.g. `"value": "value"`
+    Leaf(String),
+    /// A named object containing child nodes, e.g. `{ "age": "age", "value": "value" }`
+    Object(Vec<(String, StructureNode)>),
+    /// An array wrapping a single node, e.g. `[ { "age": "age", "value": "value" } ]`
+    Array(Box<StructureNode>),
+}
+
+impl StructureNode {
+    /// Serializes this node to the JSON fragment the API's `structure` parameter expects
+    pub fn to_json_fragment(&self) -> String {
+        match self {
+            StructureNode::Leaf(value) => format!("\"{}\"", value),
+            StructureNode::Object(fields) => {
+                let mut fragment = String::from("{");
+                for (index, (name, node)) in fields.iter().enumerate() {
+                    write!(fragment, "\"{}\":{}", name, node.to_json_fragment()).unwrap();
+                    if index + 1 != fields.len() {
+                        fragment.push_str(",");
+                    }
+                }
+                fragment.push_str("}");
+                fragment
+            }
+            StructureNode::Array(node) => format!("[{}]", node.to_json_fragment()),
+        }
+    }
+}
+
+/// A single, typed row of the data returned by the API
+///
+/// The core `areaType`/`areaName`/`areaCode`/`date` fields are always present, and
+/// whichever metrics were requested via [`crate::Cov19api::set_structure_string`] or
+/// [`crate::Cov19api::set_structure_enum`] are captured in `extra`, keyed by their
+/// returned name
+#[derive(Debug, Clone, Deserialize)]
+pub struct Record {
+    #[serde(rename = "areaType")]
+    pub area_type: String,
+    #[serde(rename = "areaName")]
+    pub area_name: String,
+    #[serde(rename = "areaCode")]
+    pub area_code: String,
+    pub date: NaiveDate,
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn output_format_only_json_parses_as_json() {
+        assert!(OutputFormat::Json.is_json());
+        assert!(!OutputFormat::Csv.is_json());
+        assert!(!OutputFormat::Xml.is_json());
+        assert!(!OutputFormat::Jsonl.is_json());
+    }
+
+    #[test]
+    fn output_format_displays_the_lowercase_query_value() {
+        assert_eq!(OutputFormat::Json.to_string(), "json");
+        assert_eq!(OutputFormat::Csv.to_string(), "csv");
+        assert_eq!(OutputFormat::Xml.to_string(), "xml");
+        assert_eq!(OutputFormat::Jsonl.to_string(), "jsonl");
+    }
+
+    #[test]
+    fn record_deserializes_camel_case_fields_and_captures_extra_metrics() {
+        let record: Record = serde_json::from_value(json!({
+            "areaType": "nation",
+            "areaName": "England",
+            "areaCode": "E92000001",
+            "date": "2020-04-01",
+            "newCasesByPublishDate": 1234,
+        }))
+        .unwrap();
+        assert_eq!(record.area_type, "nation");
+        assert_eq!(record.area_name, "England");
+        assert_eq!(record.area_code, "E92000001");
+        assert_eq!(record.date, NaiveDate::from_ymd(2020, 4, 1));
+        assert_eq!(
+            record.extra.get("newCasesByPublishDate"),
+            Some(&json!(1234))
+        );
+    }
+
+    #[test]
+    fn structure_node_leaf_renders_a_quoted_string() {
+        let node = StructureNode::Leaf(String::from("age"));
+        assert_eq!(node.to_json_fragment(), "\"age\"");
+    }
+
+    #[test]
+    fn structure_node_object_renders_its_fields_in_order() {
+        let node = StructureNode::Object(vec![
+            (String::from("age"), StructureNode::Leaf(String::from("age"))),
+            (
+                String::from("value"),
+                StructureNode::Leaf(String::from("value")),
+            ),
+        ]);
+        assert_eq!(
+            node.to_json_fragment(),
+            "{\"age\":\"age\",\"value\":\"value\"}"
+        );
+    }
+
+    #[test]
+    fn structure_node_array_wraps_its_inner_node_in_brackets() {
+        let node = StructureNode::Array(Box::new(StructureNode::Object(vec![
+            (String::from("age"), StructureNode::Leaf(String::from("age"))),
+            (
+                String::from("value"),
+                StructureNode::Leaf(String::from("value")),
+            ),
+        ])));
+        assert_eq!(
+            node.to_json_fragment(),
+            "[{\"age\":\"age\",\"value\":\"value\"}]"
+        );
+    }
+
+    #[test]
+    fn filter_set_preserves_insertion_order() {
+        let mut filters = FilterSet::new();
+        filters
+            .insert(Filters::areaName, String::from("england"))
+            .unwrap();
+        filters
+            .insert(Filters::areaType, String::from("nation"))
+            .unwrap();
+        let names: Vec<Filters> = filters.iter().map(|(name, _)| name.clone()).collect();
+        assert_eq!(names, vec![Filters::areaName, Filters::areaType]);
+    }
+
+    #[test]
+    fn filter_set_rejects_conflicting_area_type() {
+        let mut filters = FilterSet::new();
+        filters
+            .insert(Filters::areaType, String::from("nation"))
+            .unwrap();
+        assert!(filters
+            .insert(Filters::areaType, String::from("region"))
+            .is_err());
+    }
+
+    #[test]
+    fn filter_set_allows_repeating_the_same_area_type() {
+        let mut filters = FilterSet::new();
+        filters
+            .insert(Filters::areaType, String::from("nation"))
+            .unwrap();
+        assert!(filters
+            .insert(Filters::areaType, String::from("nation"))
+            .is_ok());
+    }
+
+    #[test]
+    fn filter_set_validates_area_code_against_existing_area_type() {
+        let mut filters = FilterSet::new();
+        filters
+            .insert(Filters::areaType, String::from("nation"))
+            .unwrap();
+        assert!(filters
+            .insert(Filters::areaCode, String::from("E92000001"))
+            .is_ok());
+        let mut filters = FilterSet::new();
+        filters
+            .insert(Filters::areaType, String::from("nation"))
+            .unwrap();
+        assert!(filters
+            .insert(Filters::areaCode, String::from("E06000001"))
+            .is_err());
+    }
+
+    #[test]
+    fn filter_set_validates_area_code_against_area_type_set_afterwards() {
+        let mut filters = FilterSet::new();
+        filters
+            .insert(Filters::areaCode, String::from("E06000001"))
+            .unwrap();
+        assert!(filters
+            .insert(Filters::areaType, String::from("nation"))
+            .is_err());
+    }
+}